@@ -5,23 +5,32 @@ use std::println;
 use std::str::FromStr;
 use std::sync::Arc;
 
-use async_graphql_parser::types::Type;
 use kdl::KdlDocument;
+use serde::Deserialize;
 use trustfall::provider::{
-    BasicAdapter, ContextIterator, ContextOutcomeIterator, EdgeParameters, VertexIterator,
+    AsVertex, BasicAdapter, ContextIterator, ContextOutcomeIterator, EdgeParameters, Typename,
+    VertexIterator,
 };
 use trustfall::FieldValue;
 use trustfall_core::ir::{
-    ContextField, Eid, IREdge, IRQuery, IRQueryComponent, IRVertex, IndexedQuery,
-    Output as TFOutput, Vid,
+    Argument, ContextField, Eid, FieldRef, IREdge, IRFold, IRQuery, IRQueryComponent, IRVertex,
+    IndexedQuery, LocalField, Operation, Output as TFOutput, Recursive, Type, Vid, VariableRef,
 };
 
 #[derive(Debug, Clone)]
-struct Vertex(Arc<serde_yaml::Value>);
+struct Vertex(Arc<serde_yaml::Value>, Option<Arc<str>>);
 
 impl From<&serde_yaml::Value> for Vertex {
     fn from(value: &serde_yaml::Value) -> Self {
-        Self(Arc::new(value.clone()))
+        Self(Arc::new(value.clone()), None)
+    }
+}
+
+impl Vertex {
+    /// A vertex produced by wildcarding over a mapping's entries, which
+    /// remembers the entry's key so `resolve_property("@key")` can surface it.
+    fn with_key(value: &serde_yaml::Value, key: Arc<str>) -> Self {
+        Self(Arc::new(value.clone()), Some(key))
     }
 }
 
@@ -40,7 +49,10 @@ impl trustfall::provider::Typename for Vertex {
 }
 
 struct YamlAdapter {
-    root: Arc<serde_yaml::Value>,
+    /// One entry per `---`-separated document in the source file, so a single
+    /// query fans out over every Deployment/Service/ConfigMap etc. a manifest
+    /// file happens to contain.
+    documents: Vec<Arc<serde_yaml::Value>>,
 }
 
 impl<'vertex> BasicAdapter<'vertex> for YamlAdapter {
@@ -51,93 +63,520 @@ impl<'vertex> BasicAdapter<'vertex> for YamlAdapter {
         _edge_name: &str,
         _parameters: &EdgeParameters,
     ) -> VertexIterator<'vertex, Self::Vertex> {
-        Box::new(vec![Vertex(self.root.clone())].into_iter())
+        Box::new(
+            self.documents
+                .clone()
+                .into_iter()
+                .map(|document| Vertex(document, None)),
+        )
     }
 
-    fn resolve_property(
+    fn resolve_property<V: AsVertex<Self::Vertex> + 'vertex>(
         &self,
-        contexts: ContextIterator<'vertex, Self::Vertex>,
+        contexts: ContextIterator<'vertex, V>,
         type_name: &str,
         property_name: &str,
-    ) -> ContextOutcomeIterator<'vertex, Self::Vertex, FieldValue> {
+    ) -> ContextOutcomeIterator<'vertex, V, FieldValue> {
         let type_name = type_name.to_string();
         let property_name = property_name.to_string();
-        Box::new(contexts.filter_map(move |ctx| {
-            let node = ctx.active_vertex().clone().unwrap();
+        Box::new(contexts.map(move |ctx| {
+            let node = ctx.active_vertex::<Self::Vertex>();
 
             println!("Looking for {property_name} of type {type_name} on {node:?}:");
-            node.0
-                .get(&property_name)
-                .and_then(|v| v.as_str())
-                .map(|v| (ctx.clone(), FieldValue::from(v)))
+            let value = if property_name == "@key" {
+                node.and_then(|node| node.1.clone())
+                    .map(|key| FieldValue::from(key.as_ref()))
+                    .unwrap_or(FieldValue::Null)
+            } else {
+                node.and_then(|node| node.0.get(&property_name).map(yaml_to_field_value))
+                    .unwrap_or(FieldValue::Null)
+            };
+            (ctx.clone(), value)
         }))
     }
 
-    fn resolve_neighbors(
+    fn resolve_neighbors<V: AsVertex<Self::Vertex> + 'vertex>(
         &self,
-        contexts: ContextIterator<'vertex, Self::Vertex>,
+        contexts: ContextIterator<'vertex, V>,
         _type_name: &str,
         edge_name: &str,
         _parameters: &EdgeParameters,
-    ) -> ContextOutcomeIterator<'vertex, Self::Vertex, VertexIterator<'vertex, Self::Vertex>> {
+    ) -> ContextOutcomeIterator<'vertex, V, VertexIterator<'vertex, Self::Vertex>> {
         let edge_name = edge_name.to_string();
-        Box::new(contexts.filter_map(move |context| {
+        // Every context must produce exactly one entry here, even when the YAML
+        // key is absent - yielding an empty neighbor iterator instead of
+        // dropping the context lets the interpreter decide whether the missing
+        // edge eliminates the row (required) or nulls it out (optional).
+        Box::new(contexts.map(move |context| {
             let edge_name = edge_name.clone();
-            let active = context.active_vertex().unwrap().clone();
-
-            if edge_name == "*" && active.0.is_sequence() {
-                let children: Vec<_> = active
-                    .0
-                    .as_sequence()
-                    .unwrap()
-                    .into_iter()
-                    .map(|v| Vertex::from(v))
-                    .collect();
-
-                return Some((context, Box::new(children.into_iter()) as Box<_>));
+            // Nested under an absent `(optional)` edge, the active vertex is
+            // `None` - same as `resolve_property`, that means an empty result
+            // here rather than a row-dropping `unwrap()`.
+            let active = match context.active_vertex::<Self::Vertex>().cloned() {
+                Some(active) => active,
+                None => return (context, Box::new(std::iter::empty()) as Box<_>),
+            };
+
+            // "@key" isn't a real YAML key - it's metadata about the current
+            // wildcarded vertex itself, so the "edge" just loops back to it
+            // rather than looking anything up.
+            if edge_name == "@key" {
+                let children = vec![active.clone()].into_iter();
+                return (context, Box::new(children) as Box<_>);
+            }
+
+            if edge_name == "*" {
+                if active.0.is_sequence() {
+                    let children: Vec<_> = active
+                        .0
+                        .as_sequence()
+                        .unwrap()
+                        .iter()
+                        .map(Vertex::from)
+                        .collect();
+
+                    return (context, Box::new(children.into_iter()) as Box<_>);
+                }
+
+                if let Some(mapping) = active.0.as_mapping() {
+                    let children: Vec<_> = mapping
+                        .iter()
+                        .map(|(key, value)| {
+                            let key = key.as_str().map(str::to_string).unwrap_or_default();
+                            Vertex::with_key(value, Arc::from(key))
+                        })
+                        .collect();
+
+                    return (context, Box::new(children.into_iter()) as Box<_>);
+                }
             }
 
             if let Some(value) = active.0.get(edge_name) {
                 let children = vec![Vertex::from(value)].into_iter();
-                return Some((context.clone(), Box::new(children) as Box<_>));
+                return (context.clone(), Box::new(children) as Box<_>);
             }
 
-            None
+            (context, Box::new(std::iter::empty()) as Box<_>)
         }))
     }
 
-    fn resolve_coercion(
+    fn resolve_coercion<V: AsVertex<Self::Vertex> + 'vertex>(
         &self,
-        _contexts: ContextIterator<'vertex, Self::Vertex>,
+        contexts: ContextIterator<'vertex, V>,
         _type_name: &str,
-        _coerce_to_type: &str,
-    ) -> ContextOutcomeIterator<'vertex, Self::Vertex, bool> {
-        todo!()
+        coerce_to_type: &str,
+    ) -> ContextOutcomeIterator<'vertex, V, bool> {
+        let coerce_to_type = coerce_to_type.to_string();
+        Box::new(contexts.map(move |ctx| {
+            let can_coerce = ctx
+                .active_vertex::<Self::Vertex>()
+                .map(|vertex| vertex.typename() == coerce_to_type)
+                .unwrap_or(false);
+            (ctx.clone(), can_coerce)
+        }))
     }
 }
 
+/// Maps a YAML value to the `FieldValue` the interpreter should see it as.
+/// Mappings and tagged values have no scalar representation, so they resolve
+/// to `Null` - querying them only makes sense through `resolve_neighbors`.
+fn yaml_to_field_value(value: &serde_yaml::Value) -> FieldValue {
+    match value {
+        serde_yaml::Value::Null => FieldValue::Null,
+        serde_yaml::Value::Bool(b) => FieldValue::Boolean(*b),
+        serde_yaml::Value::Number(n) => match n.as_i64() {
+            Some(i) => FieldValue::Int64(i),
+            None => n.as_f64().map(FieldValue::Float64).unwrap_or(FieldValue::Null),
+        },
+        serde_yaml::Value::String(s) => FieldValue::from(s.as_str()),
+        serde_yaml::Value::Sequence(seq) => {
+            FieldValue::List(seq.iter().map(yaml_to_field_value).collect::<Vec<_>>().into())
+        }
+        serde_yaml::Value::Mapping(_) | serde_yaml::Value::Tagged(_) => FieldValue::Null,
+    }
+}
+
+/// Infers the GraphQL-ish `Type` an output field should declare, from a sample
+/// of the YAML value it will resolve to. Falls back to `String` when there's
+/// no sample to go on (e.g. the key doesn't exist in the example document).
+fn infer_type_from_yaml(value: Option<&serde_yaml::Value>) -> Type {
+    let name = match value {
+        Some(serde_yaml::Value::Bool(_)) => "Boolean",
+        Some(serde_yaml::Value::Number(n)) if n.as_i64().is_some() => "Int",
+        Some(serde_yaml::Value::Number(_)) => "Float",
+        _ => "String",
+    };
+    Type::new_named_type(name, true)
+}
+
 struct Query(KdlDocument);
 
+/// Strips the surrounding quotes `kdl::KdlValue::to_string()` renders for string
+/// literals, giving back the bare text an author typed between the quotes.
+fn unquote(raw: &str) -> &str {
+    raw.strip_prefix('"')
+        .and_then(|v| v.strip_suffix('"'))
+        .unwrap_or(raw)
+}
+
+/// The comparisons a KDL filter value can compile down to. Mirrors the subset of
+/// `trustfall_core::ir::Operation` variants that make sense for a single scalar
+/// property, plus the shorthand prefixes recognised on an entry's value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FilterOp {
+    Equals,
+    NotEquals,
+    LessThan,
+    GreaterThan,
+    Contains,
+    HasPrefix,
+    RegexMatches,
+    OneOf,
+}
+
+impl FilterOp {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "=" | "equals" => Some(Self::Equals),
+            "!=" | "notequals" => Some(Self::NotEquals),
+            "<" | "lessthan" => Some(Self::LessThan),
+            ">" | "greaterthan" => Some(Self::GreaterThan),
+            "~" | "contains" => Some(Self::Contains),
+            "^" | "hasprefix" | "prefix" => Some(Self::HasPrefix),
+            "regex" | "regexmatches" => Some(Self::RegexMatches),
+            "oneof" => Some(Self::OneOf),
+            _ => None,
+        }
+    }
+
+    /// Recognises the shorthand prefix characters, e.g. `"=Deployment"` or `">2"`.
+    fn from_shorthand(value: &str) -> Option<(Self, &str)> {
+        if let Some(rest) = value.strip_prefix("!=") {
+            return Some((Self::NotEquals, rest));
+        }
+        for (prefix, op) in [
+            ("=", Self::Equals),
+            ("<", Self::LessThan),
+            (">", Self::GreaterThan),
+            ("~", Self::Contains),
+            ("^", Self::HasPrefix),
+        ] {
+            if let Some(rest) = value.strip_prefix(prefix) {
+                return Some((op, rest));
+            }
+        }
+        None
+    }
+
+    fn into_operation(self, field: LocalField, argument: Argument) -> Operation<LocalField, Argument> {
+        match self {
+            Self::Equals => Operation::Equals(field, argument),
+            Self::NotEquals => Operation::NotEquals(field, argument),
+            Self::LessThan => Operation::LessThan(field, argument),
+            Self::GreaterThan => Operation::GreaterThan(field, argument),
+            Self::Contains => Operation::Contains(field, argument),
+            Self::HasPrefix => Operation::HasPrefix(field, argument),
+            Self::RegexMatches => Operation::RegexMatches(field, argument),
+            Self::OneOf => Operation::OneOf(field, argument),
+        }
+    }
+}
+
+/// A parsed `@filter`-equivalent: the comparison to run plus the operand it was
+/// given, as written but with its quotes removed. The operand is either a literal
+/// or, when it starts with `%`, a reference to a tag declared earlier.
+struct ParsedFilter {
+    op: FilterOp,
+    operand: String,
+}
+
+/// Returns the unquoted value of the node's entry named `entry_name`, e.g. the
+/// `nginx` of `value="nginx"`, or `None` when the node has no such entry.
+fn named_entry(node: &kdl::KdlNode, entry_name: &str) -> Option<String> {
+    node.entries()
+        .iter()
+        .find(|e| e.name().map(|n| n.value()) == Some(entry_name))
+        .map(|e| unquote(&e.value().to_string()).to_string())
+}
+
+/// Looks for either the explicit `op="contains" value="nginx"` form or the
+/// shorthand prefix baked into the node's first anonymous entry, e.g. `"~nginx"`.
+/// Returns `None` for plain captures (`"@name"`) and for entries that carry no
+/// recognisable operator at all, which is left as a no-op for backwards
+/// compatibility with existing queries.
+fn parse_filter(node: &kdl::KdlNode) -> Option<ParsedFilter> {
+    let named_op = named_entry(node, "op");
+    let named_value = named_entry(node, "value");
+
+    if let (Some(op), Some(operand)) = (named_op, named_value) {
+        return FilterOp::from_name(&op).map(|op| ParsedFilter { op, operand });
+    }
+
+    let anonymous = node.entries().iter().find(|e| e.name().is_none())?;
+    let raw = anonymous.value().to_string();
+    let value = unquote(&raw);
+    if value.starts_with('@') {
+        return None;
+    }
+
+    let (op, operand) = FilterOp::from_shorthand(value)?;
+    Some(ParsedFilter {
+        op,
+        operand: operand.to_string(),
+    })
+}
+
+/// Converts a filter's literal operand into the most specific `FieldValue` it
+/// looks like, so `">2"` compares against replicas as a number rather than a
+/// string. Falls back to a string when nothing more specific matches.
+fn literal_field_value(operand: &str) -> FieldValue {
+    if let Ok(i) = operand.parse::<i64>() {
+        return FieldValue::Int64(i);
+    }
+    if let Ok(f) = operand.parse::<f64>() {
+        return FieldValue::Float64(f);
+    }
+    if let Ok(b) = operand.parse::<bool>() {
+        return FieldValue::Boolean(b);
+    }
+    FieldValue::from(operand)
+}
+
+/// Reads a `recurse=N` entry off a node, e.g. `spec recurse=3 { ... }`, and
+/// validates `N` is a positive integer depth. Panics with a clear message on a
+/// non-numeric or non-positive value, since a malformed query is a programmer
+/// error in the query text, not a runtime condition callers should recover from.
+fn parse_recurse_depth(node: &kdl::KdlNode) -> Option<NonZeroUsize> {
+    let raw = named_entry(node, "recurse")?;
+    let depth: usize = raw
+        .parse()
+        .unwrap_or_else(|_| panic!("recurse depth must be a positive integer, got {raw:?}"));
+    Some(NonZeroUsize::new(depth).unwrap_or_else(|| panic!("recurse depth must be greater than 0")))
+}
+
+fn graphql_type_for(value: &FieldValue) -> Type {
+    let name = match value {
+        FieldValue::Int64(_) => "Int",
+        FieldValue::Float64(_) => "Float",
+        FieldValue::Boolean(_) => "Boolean",
+        _ => "String",
+    };
+    Type::new_named_type(name, true)
+}
+
 type Vertices = BTreeMap<Vid, IRVertex>;
 type Edges = BTreeMap<Eid, Arc<IREdge>>;
 type Outputs = BTreeMap<Arc<str>, TFOutput>;
+type Filters = BTreeMap<Vid, Vec<Operation<LocalField, Argument>>>;
+type Variables = BTreeMap<Arc<str>, Type>;
+type Arguments = BTreeMap<Arc<str>, FieldValue>;
+type Folds = BTreeMap<Eid, Arc<IRFold>>;
+/// Tracks `@tag`-equivalent captures (`"%name"`) in document order: tag name ->
+/// the field it refers to. Shared across the whole recursive walk so a filter
+/// can reference any tag declared on an ancestor or an earlier sibling.
+type TagTable = BTreeMap<String, ContextField>;
+
+/// Everything gathered while walking a (sub)document of the KDL query. Kept as
+/// one struct so recursive calls can merge their findings into the caller's
+/// without juggling an ever-growing tuple.
+#[derive(Default)]
+struct QueryComponentParts {
+    vertices: Vertices,
+    edges: Edges,
+    outputs: Outputs,
+    filters: Filters,
+    variables: Variables,
+    arguments: Arguments,
+    folds: Folds,
+}
+
+impl QueryComponentParts {
+    fn merge(&mut self, other: Self) {
+        self.vertices.extend(other.vertices);
+        self.edges.extend(other.edges);
+        self.outputs.extend(other.outputs);
+        for (vid, ops) in other.filters {
+            self.filters.entry(vid).or_default().extend(ops);
+        }
+        self.variables.extend(other.variables);
+        self.arguments.extend(other.arguments);
+        self.folds.extend(other.folds);
+    }
+
+    /// Applies the accumulated filters onto their owning vertices. Filters are
+    /// collected separately from `vertices` while walking the document because a
+    /// node's filter belongs to its *parent* vertex, which may have been built in
+    /// an outer call frame.
+    fn apply_filters(&mut self) {
+        for (vid, ops) in std::mem::take(&mut self.filters) {
+            if let Some(vertex) = self.vertices.get_mut(&vid) {
+                vertex.filters.extend(ops);
+            }
+        }
+    }
+}
+
+/// Turns the flat `name -> TFOutput` bookkeeping used while walking the
+/// document into the `ContextField`s an `IRQueryComponent` wants for its own
+/// `outputs` map.
+fn to_context_fields(outputs: &Outputs) -> BTreeMap<Arc<str>, ContextField> {
+    outputs
+        .iter()
+        .map(|(key, output)| {
+            (
+                key.clone(),
+                ContextField {
+                    vertex_id: output.vid,
+                    field_name: output.name.clone(),
+                    field_type: output.value_type.clone(),
+                },
+            )
+        })
+        .collect()
+}
+
+/// Builds the nested `IRFold` for a node carrying the `(fold)` type annotation,
+/// e.g. `(fold)containers { * { image "@image" } }`. The fold's subtree gets its
+/// own `IRQueryComponent` rooted at `to_vid`, so its outputs are collected per
+/// fold iteration and surfaced as a single `FieldValue::List` rather than one
+/// output row per element.
+#[allow(clippy::too_many_arguments)]
+fn construct_fold(
+    node: &kdl::KdlNode,
+    name: &str,
+    parent_vid: Vid,
+    to_vid: Vid,
+    vid_maker: &mut impl Iterator<Item = Vid>,
+    eid_maker: &mut impl Iterator<Item = Eid>,
+    variable_id_maker: &mut impl Iterator<Item = String>,
+    tag_table: &mut TagTable,
+    sample: Option<&serde_yaml::Value>,
+) -> QueryComponentParts {
+    let mut fold_parts = QueryComponentParts::default();
+    fold_parts.vertices.insert(
+        to_vid,
+        IRVertex {
+            vid: to_vid,
+            type_name: Arc::from("node"),
+            coerced_from_type: None,
+            filters: Vec::new(),
+        },
+    );
+
+    // Like a regular edge, the fold's own Eid must be claimed before recursing
+    // into its children - `IndexedQuery` requires `fold.eid + 1 == fold.to_vid`,
+    // which only holds if no nested edge gets to consume an Eid first.
+    let fold_eid = eid_maker.next().unwrap();
+
+    if let Some(children) = node.children() {
+        let child = construct_edges(
+            children,
+            to_vid,
+            vid_maker,
+            eid_maker,
+            variable_id_maker,
+            tag_table,
+            sample,
+        );
+        fold_parts.merge(child);
+    }
+    fold_parts.apply_filters();
+
+    let fold_outputs = std::mem::take(&mut fold_parts.outputs);
+    let nested_component = IRQueryComponent {
+        root: to_vid,
+        vertices: std::mem::take(&mut fold_parts.vertices),
+        edges: std::mem::take(&mut fold_parts.edges),
+        folds: std::mem::take(&mut fold_parts.folds),
+        outputs: to_context_fields(&fold_outputs),
+    };
+
+    fold_parts.folds.insert(
+        fold_eid,
+        Arc::new(IRFold {
+            eid: fold_eid,
+            from_vid: parent_vid,
+            to_vid,
+            edge_name: Arc::from(name),
+            parameters: EdgeParameters::default(),
+            component: Arc::from(nested_component),
+            post_filters: Vec::new(),
+            imported_tags: Vec::new(),
+            fold_specific_outputs: BTreeMap::new(),
+        }),
+    );
+
+    // Unlike a plain edge's outputs, a fold's outputs stay out of
+    // `fold_parts.outputs`: they live on vertices inside `nested_component`,
+    // not this component, and `IndexedQuery`'s construction already walks
+    // `component.folds` recursively to promote them into the top-level output
+    // map, wrapping each one as a list as it goes.
+
+    fold_parts
+}
+
+/// Looks up the YAML value a KDL node's field would resolve to, given the
+/// sample value at its parent. Used purely to infer output types statically;
+/// has no bearing on what the adapter actually resolves at query time.
+fn sample_field<'a>(
+    sample: Option<&'a serde_yaml::Value>,
+    name: &str,
+) -> Option<&'a serde_yaml::Value> {
+    if name == "*" {
+        sample
+            .and_then(|v| v.as_sequence())
+            .and_then(|s| s.first())
+            .or_else(|| sample.and_then(|v| v.as_mapping()).and_then(|m| m.values().next()))
+    } else {
+        sample.and_then(|v| v.get(name))
+    }
+}
 
 fn construct_edges(
     doc: &KdlDocument,
     parent_vid: Vid,
     vid_maker: &mut impl Iterator<Item = Vid>,
     eid_maker: &mut impl Iterator<Item = Eid>,
-) -> (Vertices, Edges, Outputs) {
-    let mut vertices = Vertices::new();
-    let mut edges = Edges::new();
-    let mut outputs = Outputs::new();
+    variable_id_maker: &mut impl Iterator<Item = String>,
+    tag_table: &mut TagTable,
+    sample: Option<&serde_yaml::Value>,
+) -> QueryComponentParts {
+    let mut parts = QueryComponentParts::default();
 
     for node in doc.nodes() {
         let next_vid = vid_maker.next().unwrap();
-        let name = node.name().value();
+        let raw_name = node.name().value();
+        // `name?` is shorthand for the `(optional)` type annotation - either marks
+        // the edge as one the interpreter should null out, rather than drop the
+        // whole row over, when the YAML key turns out to be absent.
+        let (name, is_optional) = match raw_name.strip_suffix('?') {
+            Some(stripped) => (stripped, true),
+            None => (
+                raw_name,
+                node.ty().map(|ty| ty.value() == "optional").unwrap_or(false),
+            ),
+        };
         dbg!(name);
 
-        vertices.insert(
+        let field_sample = sample_field(sample, name);
+
+        let is_fold = node.ty().map(|ty| ty.value() == "fold").unwrap_or(false);
+        if is_fold {
+            parts.merge(construct_fold(
+                node,
+                name,
+                parent_vid,
+                next_vid,
+                vid_maker,
+                eid_maker,
+                variable_id_maker,
+                tag_table,
+                field_sample,
+            ));
+            continue;
+        }
+
+        parts.vertices.insert(
             next_vid,
             IRVertex {
                 vid: next_vid,
@@ -147,29 +586,74 @@ fn construct_edges(
             },
         );
 
-        if let Some(entry) = node.entries().first() {
-            let v = entry.value().to_string();
-
-            let output_name = v
-                .strip_prefix("\"@")
-                .and_then(|v| v.strip_suffix("\""))
-                .unwrap_or(&v);
+        if let Some(entry) = node.entries().iter().find(|e| e.name().is_none()) {
+            let raw = entry.value().to_string();
+            let value = unquote(&raw);
 
-            if v.starts_with(r#""@"#) {
-                outputs.insert(
+            if let Some(output_name) = value.strip_prefix('@') {
+                parts.outputs.insert(
                     Arc::from(output_name),
                     TFOutput {
                         name: Arc::from(name),
-                        value_type: Type::new("String").unwrap(),
+                        value_type: infer_type_from_yaml(field_sample),
                         vid: parent_vid,
                     },
                 );
+            } else if let Some(tag_name) = value.strip_prefix('%') {
+                tag_table.insert(
+                    tag_name.to_string(),
+                    ContextField {
+                        vertex_id: parent_vid,
+                        field_name: Arc::from(name),
+                        field_type: infer_type_from_yaml(field_sample),
+                    },
+                );
             }
         }
 
+        if let Some(parsed) = parse_filter(node) {
+            let field_type;
+            let argument = if let Some(tag_name) = parsed.operand.strip_prefix('%') {
+                let field_ref = tag_table.get(tag_name).unwrap_or_else(|| {
+                    panic!(
+                        "filter on `{name}` references tag `%{tag_name}`, \
+                         which hasn't been declared by an earlier `\"%{tag_name}\"` capture"
+                    )
+                });
+                field_type = field_ref.field_type.clone();
+                Argument::Tag(FieldRef::ContextField(field_ref.clone()))
+            } else {
+                let value = literal_field_value(&parsed.operand);
+                let variable_name: Arc<str> = Arc::from(variable_id_maker.next().unwrap());
+                let variable_type = graphql_type_for(&value);
+                field_type = variable_type.clone();
+
+                parts
+                    .variables
+                    .insert(variable_name.clone(), variable_type.clone());
+                parts.arguments.insert(variable_name.clone(), value);
+
+                Argument::Variable(VariableRef {
+                    variable_name,
+                    variable_type,
+                })
+            };
+
+            let field = LocalField {
+                field_name: Arc::from(name),
+                field_type,
+            };
+            parts
+                .filters
+                .entry(parent_vid)
+                .or_default()
+                .push(parsed.op.into_operation(field, argument));
+        }
+
         let parent_to_needle = eid_maker.next().unwrap();
+        let recursive = parse_recurse_depth(node).map(|depth| Recursive::new(depth, None));
 
-        edges.insert(
+        parts.edges.insert(
             parent_to_needle,
             Arc::new(IREdge {
                 eid: parent_to_needle,
@@ -177,35 +661,43 @@ fn construct_edges(
                 to_vid: next_vid,
                 edge_name: Arc::from(name),
                 parameters: EdgeParameters::default(),
-                optional: false,
-                recursive: None,
+                optional: is_optional,
+                recursive,
             }),
         );
 
         if let Some(d) = node.children() {
-            let (v, e, o) = construct_edges(d, next_vid, vid_maker, eid_maker);
-            vertices.extend(v);
-            edges.extend(e);
-            outputs.extend(o);
+            let child = construct_edges(
+                d,
+                next_vid,
+                vid_maker,
+                eid_maker,
+                variable_id_maker,
+                tag_table,
+                field_sample,
+            );
+            parts.merge(child);
         }
     }
-    (vertices, edges, outputs)
+    parts
 }
 
 impl Query {
-    pub fn iquery_and_arguments(self) -> (IndexedQuery, BTreeMap<Arc<str>, FieldValue>) {
+    pub fn iquery_and_arguments(
+        self,
+        sample: Option<&serde_yaml::Value>,
+    ) -> (IndexedQuery, BTreeMap<Arc<str>, FieldValue>) {
         let mut vid_maker =
             successors(Some(1), |n| Some(n + 1)).map(|n| Vid::new(NonZeroUsize::new(n).unwrap()));
         let mut eid_maker =
             successors(Some(1), |n| Some(n + 1)).map(|n| Eid::new(NonZeroUsize::new(n).unwrap()));
-        let _variable_id_maker = successors(Some(1), |n| Some(n + 1)).map(|n| n.to_string());
-
-        let mut vertices = BTreeMap::default();
-        let mut edges = BTreeMap::default();
+        let mut variable_id_maker =
+            successors(Some(1), |n| Some(n + 1)).map(|n| format!("var_{n}"));
 
         let starting_vid = vid_maker.next().unwrap();
 
-        vertices.insert(
+        let mut parts = QueryComponentParts::default();
+        parts.vertices.insert(
             starting_vid,
             IRVertex {
                 vid: starting_vid,
@@ -217,42 +709,41 @@ impl Query {
 
         // let starting_point = self.0.get("doc").expect("Every query must start with doc");
 
-        let (v, e, o) = construct_edges(&self.0, starting_vid, &mut vid_maker, &mut eid_maker);
-        vertices.extend(v);
-        edges.extend(e);
+        let mut tag_table = TagTable::new();
+        let child = construct_edges(
+            &self.0,
+            starting_vid,
+            &mut vid_maker,
+            &mut eid_maker,
+            &mut variable_id_maker,
+            &mut tag_table,
+            sample,
+        );
+        parts.merge(child);
+        parts.apply_filters();
 
         let query_component = IRQueryComponent {
             root: starting_vid,
-            vertices,
-            edges,
-            folds: Default::default(),
-            outputs: o
-                .iter()
-                .map(|(key, output)| {
-                    (
-                        key.clone(),
-                        ContextField {
-                            vertex_id: output.vid,                 // on this Vertex...
-                            field_name: output.name.clone(),       // ...look for this field...
-                            field_type: output.value_type.clone(), // ...with this type...
-                        },
-                    )
-                })
-                .collect(),
+            vertices: parts.vertices,
+            edges: parts.edges,
+            folds: parts.folds,
+            outputs: to_context_fields(&parts.outputs),
         };
 
         let ir_query = IRQuery {
             root_name: Arc::from("Document"),
             root_parameters: EdgeParameters::default(),
             root_component: Arc::from(query_component),
-            variables: BTreeMap::new(),
+            variables: parts.variables,
         };
 
-        let mut query: IndexedQuery = ir_query.try_into().unwrap();
-        query.outputs = o;
-        let arguments = BTreeMap::new();
+        // `TryFrom<IRQuery>` already walks `component.folds` recursively and
+        // populates `outputs` with every output - including ones nested under
+        // a `@fold`, correctly wrapped in a list type - so there's nothing left
+        // to add here.
+        let query: IndexedQuery = ir_query.try_into().unwrap();
 
-        (query, arguments)
+        (query, parts.arguments)
     }
 }
 
@@ -260,12 +751,14 @@ type Outcomes = Vec<BTreeMap<String, FieldValue>>;
 
 pub fn run(raw_query: &str, yaml: &str) -> Result<Outcomes, anyhow::Error> {
     let kdl_doc = kdl::KdlDocument::from_str(raw_query).unwrap();
-    let root = serde_yaml::from_str(yaml).unwrap();
+    let documents: Vec<serde_yaml::Value> = serde_yaml::Deserializer::from_str(yaml)
+        .map(serde_yaml::Value::deserialize)
+        .collect::<Result<_, _>>()?;
 
-    let (query, variables) = Query(kdl_doc).iquery_and_arguments();
+    let (query, variables) = Query(kdl_doc).iquery_and_arguments(documents.first());
 
     let adapter = YamlAdapter {
-        root: Arc::new(root),
+        documents: documents.into_iter().map(Arc::new).collect(),
     };
 
     let result: Vec<_> = trustfall_core::interpreter::execution::interpret_ir(
@@ -295,7 +788,7 @@ mod tests {
     #[test]
     fn it_works() {
         let pretend_query = indoc::indoc! {r#"
-            kind "Deployment"
+            kind "=Deployment"
             metadata {
                 name "@name"
             }
@@ -378,6 +871,281 @@ mod tests {
             ]
         )
     }
+
+    #[test]
+    fn filters_out_documents_that_do_not_match() {
+        let pretend_query = indoc::indoc! {r#"
+            kind "=Service"
+            metadata {
+                name "@name"
+            }
+        "#};
+
+        let yaml = indoc::indoc! { r#"
+            apiVersion: apps/v1
+            kind: Deployment
+            metadata:
+              name: other-server
+        "# };
+
+        let hits = run(pretend_query, yaml).unwrap();
+
+        assert_eq!(hits, vec![]);
+    }
+
+    #[test]
+    fn fold_collects_containers_into_one_row() {
+        let pretend_query = indoc::indoc! {r#"
+            kind "=Deployment"
+            spec {
+                template {
+                    spec {
+                        (fold)containers {
+                            * {
+                                image "@image"
+                            }
+                        }
+                    }
+                }
+            }
+        "#};
+
+        let yaml = indoc::indoc! { r#"
+            apiVersion: apps/v1
+            kind: Deployment
+            spec:
+              template:
+                spec:
+                  containers:
+                  - image: truelayer-docker.jfrog.io/clients-api:v1.44.19
+                  - image: truelayer-docker.jfrog.io/nginx-sidecar:v1.1.11
+                  - image: truelayer-docker.jfrog.io/envoyproxy_envoy:v1.17.0
+        "# };
+
+        let hits = run(pretend_query, yaml).unwrap();
+
+        assert_eq!(
+            hits,
+            vec![BTreeMap::from([(
+                "image".into(),
+                FieldValue::List(
+                    vec![
+                        FieldValue::from("truelayer-docker.jfrog.io/clients-api:v1.44.19"),
+                        FieldValue::from("truelayer-docker.jfrog.io/nginx-sidecar:v1.1.11"),
+                        FieldValue::from("truelayer-docker.jfrog.io/envoyproxy_envoy:v1.17.0"),
+                    ]
+                    .into()
+                )
+            )])]
+        )
+    }
+
+    #[test]
+    fn recurse_descends_through_self_similar_nesting() {
+        let pretend_query = indoc::indoc! {r#"
+            node recurse=3 {
+                value "@value"
+            }
+        "#};
+
+        let yaml = indoc::indoc! { r#"
+            node:
+              value: a
+              node:
+                value: b
+                node:
+                  value: c
+        "# };
+
+        let hits = run(pretend_query, yaml).unwrap();
+
+        assert_eq!(
+            hits,
+            vec![
+                BTreeMap::from([("value".into(), FieldValue::from("a"))]),
+                BTreeMap::from([("value".into(), FieldValue::from("b"))]),
+                BTreeMap::from([("value".into(), FieldValue::from("c"))]),
+            ]
+        )
+    }
+
+    #[test]
+    fn tag_compares_a_later_field_against_an_earlier_one() {
+        let pretend_query = indoc::indoc! {r#"
+            metadata {
+                name "%expected"
+            }
+            spec {
+                template {
+                    metadata {
+                        labels {
+                            app "=%expected"
+                        }
+                    }
+                }
+            }
+        "#};
+
+        let matching_yaml = indoc::indoc! { r#"
+            metadata:
+              name: other-server
+            spec:
+              template:
+                metadata:
+                  labels:
+                    app: other-server
+        "# };
+
+        let hits = run(pretend_query, matching_yaml).unwrap();
+        assert_eq!(hits, vec![BTreeMap::new()]);
+
+        let mismatching_yaml = indoc::indoc! { r#"
+            metadata:
+              name: other-server
+            spec:
+              template:
+                metadata:
+                  labels:
+                    app: something-else
+        "# };
+
+        let hits = run(pretend_query, mismatching_yaml).unwrap();
+        assert_eq!(hits, vec![]);
+    }
+
+    #[test]
+    fn optional_edge_keeps_rows_missing_that_key() {
+        let pretend_query = indoc::indoc! {r#"
+            items {
+                * {
+                    id "@id"
+                    detail? {
+                        label "@label"
+                    }
+                }
+            }
+        "#};
+
+        let yaml = indoc::indoc! { r#"
+            items:
+            - id: "1"
+              detail:
+                label: first
+            - id: "2"
+        "# };
+
+        let hits = run(pretend_query, yaml).unwrap();
+
+        assert_eq!(
+            hits,
+            vec![
+                BTreeMap::from([
+                    ("id".into(), FieldValue::from("1")),
+                    ("label".into(), FieldValue::from("first")),
+                ]),
+                BTreeMap::from([
+                    ("id".into(), FieldValue::from("2")),
+                    ("label".into(), FieldValue::Null),
+                ]),
+            ]
+        )
+    }
+
+    #[test]
+    fn resolves_numeric_and_boolean_properties() {
+        let pretend_query = indoc::indoc! {r#"
+            replicas "@replicas"
+            paused "@paused"
+        "#};
+
+        let yaml = indoc::indoc! { r#"
+            replicas: 3
+            paused: false
+        "# };
+
+        let hits = run(pretend_query, yaml).unwrap();
+
+        assert_eq!(
+            hits,
+            vec![BTreeMap::from([
+                ("replicas".into(), FieldValue::Int64(3)),
+                ("paused".into(), FieldValue::Boolean(false)),
+            ])]
+        )
+    }
+
+    #[test]
+    fn queries_fan_out_over_every_document_in_the_stream() {
+        let pretend_query = indoc::indoc! {r#"
+            kind "@kind"
+            metadata {
+                name "@name"
+            }
+        "#};
+
+        let yaml = indoc::indoc! { r#"
+            apiVersion: apps/v1
+            kind: Deployment
+            metadata:
+              name: other-server
+            ---
+            apiVersion: v1
+            kind: Service
+            metadata:
+              name: other-server-svc
+        "# };
+
+        let hits = run(pretend_query, yaml).unwrap();
+
+        assert_eq!(
+            hits,
+            vec![
+                BTreeMap::from([
+                    ("kind".into(), FieldValue::from("Deployment")),
+                    ("name".into(), FieldValue::from("other-server")),
+                ]),
+                BTreeMap::from([
+                    ("kind".into(), FieldValue::from("Service")),
+                    ("name".into(), FieldValue::from("other-server-svc")),
+                ]),
+            ]
+        )
+    }
+
+    #[test]
+    fn wildcard_over_a_mapping_captures_each_entrys_key() {
+        let pretend_query = indoc::indoc! {r#"
+            kind "=Deployment"
+            metadata {
+                (fold)annotations {
+                    * {
+                        "@key" "@annotation"
+                    }
+                }
+            }
+        "#};
+
+        let yaml = indoc::indoc! { r#"
+            apiVersion: apps/v1
+            kind: Deployment
+            metadata:
+              annotations:
+                team: clients
+                owner: platform
+        "# };
+
+        let hits = run(pretend_query, yaml).unwrap();
+
+        assert_eq!(
+            hits,
+            vec![BTreeMap::from([(
+                "annotation".into(),
+                FieldValue::List(
+                    vec![FieldValue::from("team"), FieldValue::from("owner")].into()
+                ),
+            )])]
+        )
+    }
 }
 
 // doc {